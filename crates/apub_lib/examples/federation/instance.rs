@@ -3,7 +3,7 @@ use crate::objects::{
   person::{MyUser, PersonAcceptedActivities},
 };
 use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 use http_signature_normalization_actix::prelude::VerifyDigest;
 use lemmy_apub_lib::{
   context::WithContext,
@@ -16,15 +16,41 @@ use lemmy_apub_lib::{
   APUB_JSON_CONTENT_TYPE,
 };
 use lemmy_utils::error::LemmyError;
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
 use reqwest::Client;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::{ops::Deref, sync::Arc};
+use std::{
+  collections::{HashMap, HashSet, VecDeque},
+  ops::Deref,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  time::{Duration, Instant},
+};
 use url::Url;
 
+/// Maximum number of activity ids the shared inbox remembers at once.
+const SEEN_ACTIVITIES_CAPACITY: usize = 10_000;
+/// How long an activity id is remembered for, regardless of how full the cache is.
+const SEEN_ACTIVITIES_TTL: Duration = Duration::from_secs(60 * 60);
+/// Number of outbox items per `OrderedCollectionPage`.
+const OUTBOX_PAGE_SIZE: usize = 20;
+/// Base delay before the first retry of a failed delivery.
+const DELIVERY_RETRY_BASE: Duration = Duration::from_secs(60);
+/// Upper bound on the exponential part of the backoff delay; jitter is added on top of it.
+const DELIVERY_RETRY_MAX: Duration = Duration::from_secs(60 * 60);
+/// Number of delivery attempts before an inbox is given up on and marked dead.
+const DELIVERY_MAX_ATTEMPTS: u32 = 8;
+/// How often the delivery worker wakes up to check for ready jobs.
+const DELIVERY_WORKER_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct Instance {
   local_instance: Arc<LocalInstance>,
   users: Vec<MyUser>,
   posts: Vec<MyPost>,
+  delivery_queue: Arc<dyn DeliveryQueue>,
 }
 
 impl Instance {
@@ -34,10 +60,31 @@ impl Instance {
       Client::default().into(),
       InstanceSettings::default(),
     );
+    let delivery_queue: Arc<dyn DeliveryQueue> = Arc::new(MemoryDeliveryQueue::default());
+    spawn_delivery_worker(delivery_queue.clone());
     Instance {
       local_instance: Arc::new(local_instance),
       users: vec![],
       posts: vec![],
+      delivery_queue,
+    }
+  }
+
+  /// Queues `activity` (the unsigned activity JSON, as the `actor_id` actor) for delivery to
+  /// `inbox_url`, retrying with exponential backoff on failure until [`DELIVERY_MAX_ATTEMPTS`]
+  /// is reached. The request is signed fresh on every attempt (see [`deliver`]) rather than
+  /// once at enqueue time, so a `Date` header from hours-old retries is never replayed.
+  pub fn enqueue_delivery(&self, inbox_url: Url, actor_id: Url, private_key_pem: String, activity: String) {
+    self
+      .delivery_queue
+      .enqueue(inbox_url, actor_id, private_key_pem, activity);
+  }
+
+  /// Snapshot of the outgoing delivery queue, suitable for exposing as metrics.
+  pub fn delivery_metrics(&self) -> DeliveryMetrics {
+    DeliveryMetrics {
+      pending: self.delivery_queue.pending_count(),
+      dead: self.delivery_queue.dead_count(),
     }
   }
 
@@ -55,15 +102,27 @@ impl Instance {
 
   pub async fn listen(&self) -> Result<(), Error> {
     let local_instance = self.local_instance.clone();
+    let users = self.users.clone();
+    let posts = self.posts.clone();
+    let seen_activities = Arc::new(Mutex::new(SeenActivities::new(
+      SEEN_ACTIVITIES_CAPACITY,
+      SEEN_ACTIVITIES_TTL,
+    )));
     HttpServer::new(move || {
       App::new()
         .app_data(Data::new(local_instance.clone()))
+        .app_data(Data::new(users.clone()))
+        .app_data(Data::new(posts.clone()))
+        .app_data(Data::new(seen_activities.clone()))
         // The routes
         .route("/objects/{user_name}", web::get().to(get_user))
+        .route("/.well-known/webfinger", web::get().to(webfinger))
+        .route("/u/{user_name}/outbox", web::get().to(get_outbox))
         .service(
           web::scope("")
             .wrap(VerifyDigest::new(Sha256::new()))
-            .route("/u/{user_name}/inbox", web::post().to(post_inbox)),
+            .route("/u/{user_name}/inbox", web::post().to(post_inbox))
+            .route("/inbox", web::post().to(post_shared_inbox)),
         )
     })
     .bind(self.local_instance.hostname())?
@@ -87,6 +146,204 @@ async fn get_user(request: HttpRequest) -> Result<HttpResponse, LemmyError> {
   )
 }
 
+/// Minimal WebFinger implementation, resolving an `acct:user@host` resource to the user's
+/// ActivityPub actor id. This is the discovery step every federated peer performs before it
+/// will deliver activities to a local actor.
+async fn webfinger(
+  query: web::Query<WebfingerQuery>,
+  users: web::Data<Vec<MyUser>>,
+) -> Result<HttpResponse, LemmyError> {
+  let name = match query
+    .resource
+    .strip_prefix("acct:")
+    .and_then(|acct| acct.split('@').next())
+  {
+    Some(name) => name,
+    None => return Ok(HttpResponse::NotFound().finish()),
+  };
+
+  // WebFinger must only resolve actors this server is authoritative for; a local name match
+  // is meaningless (and a spoofing risk) against cached/followed remote actors in `users`.
+  let user = match users.iter().find(|u| u.local && u.name == name) {
+    Some(user) => user,
+    None => return Ok(HttpResponse::NotFound().finish()),
+  };
+
+  Ok(HttpResponse::Ok().json(WebfingerResponse {
+    subject: query.resource.clone(),
+    links: vec![WebfingerLink {
+      rel: "self".to_string(),
+      kind: APUB_JSON_CONTENT_TYPE.to_string(),
+      href: user.ap_id.inner().clone(),
+    }],
+  }))
+}
+
+#[derive(Deserialize)]
+struct WebfingerQuery {
+  resource: String,
+}
+
+#[derive(Serialize)]
+struct WebfingerResponse {
+  subject: String,
+  links: Vec<WebfingerLink>,
+}
+
+#[derive(Serialize)]
+struct WebfingerLink {
+  rel: String,
+  #[serde(rename = "type")]
+  kind: String,
+  href: Url,
+}
+
+/// Returns the user's outbox as an `OrderedCollection` of `Create` activities, one per post.
+/// Once the post count exceeds [`OUTBOX_PAGE_SIZE`] the collection only links to the first
+/// `OrderedCollectionPage` via `?page=1`, and pages link to their `next`/`prev` siblings.
+async fn get_outbox(
+  request: HttpRequest,
+  user_name: web::Path<String>,
+  query: web::Query<OutboxQuery>,
+  users: web::Data<Vec<MyUser>>,
+  posts: web::Data<Vec<MyPost>>,
+) -> Result<HttpResponse, LemmyError> {
+  let user = match users.iter().find(|u| u.local && u.name == user_name.as_str()) {
+    Some(user) => user,
+    None => return Ok(HttpResponse::NotFound().finish()),
+  };
+  let posts: Vec<MyPost> = posts
+    .iter()
+    .filter(|p| p.creator.inner() == user.ap_id.inner())
+    .cloned()
+    .collect();
+
+  let mut collection_id = Url::parse(&request.uri().to_string())?;
+  collection_id.set_query(None);
+  let total_items = posts.len();
+
+  if let Some(page) = query.page {
+    // Pages are 1-indexed; `page=0` has no meaning and would otherwise fall through
+    // `page.saturating_sub(1)` in `outbox_page_items` and silently alias page 1.
+    if page == 0 {
+      return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let ordered_items = outbox_page_items(&posts, page).await?;
+
+    let mut page_id = collection_id.clone();
+    page_id.set_query(Some(&format!("page={page}")));
+
+    let next = if page * OUTBOX_PAGE_SIZE < total_items {
+      let mut url = collection_id.clone();
+      url.set_query(Some(&format!("page={}", page + 1)));
+      Some(url)
+    } else {
+      None
+    };
+    let prev = if page > 1 {
+      let mut url = collection_id.clone();
+      url.set_query(Some(&format!("page={}", page - 1)));
+      Some(url)
+    } else {
+      None
+    };
+
+    return Ok(
+      HttpResponse::Ok()
+        .content_type(APUB_JSON_CONTENT_TYPE)
+        .json(WithContext::new_default(OrderedCollectionPage {
+          kind: "OrderedCollectionPage",
+          id: page_id,
+          part_of: collection_id,
+          next,
+          prev,
+          ordered_items,
+        })),
+    );
+  }
+
+  let (first, ordered_items) = if total_items > OUTBOX_PAGE_SIZE {
+    let mut first = collection_id.clone();
+    first.set_query(Some("page=1"));
+    (Some(first), None)
+  } else {
+    (None, Some(outbox_page_items(&posts, 1).await?))
+  };
+
+  Ok(
+    HttpResponse::Ok()
+      .content_type(APUB_JSON_CONTENT_TYPE)
+      .json(WithContext::new_default(OrderedCollection {
+        kind: "OrderedCollection",
+        id: collection_id,
+        total_items,
+        first,
+        ordered_items,
+      })),
+  )
+}
+
+async fn outbox_page_items(
+  posts: &[MyPost],
+  page: usize,
+) -> Result<Vec<CreateActivity>, LemmyError> {
+  let start = page.saturating_sub(1) * OUTBOX_PAGE_SIZE;
+  let mut items = Vec::new();
+  for post in posts.iter().skip(start).take(OUTBOX_PAGE_SIZE) {
+    let object = post.clone().into_apub(&()).await?;
+    items.push(CreateActivity {
+      kind: "Create",
+      id: Url::parse(&format!("{}/create", post.ap_id.inner()))?,
+      actor: post.creator.inner().clone(),
+      object,
+    });
+  }
+  Ok(items)
+}
+
+#[derive(Deserialize)]
+struct OutboxQuery {
+  page: Option<usize>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OrderedCollection {
+  #[serde(rename = "type")]
+  kind: &'static str,
+  id: Url,
+  total_items: usize,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  first: Option<Url>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  ordered_items: Option<Vec<CreateActivity>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OrderedCollectionPage {
+  #[serde(rename = "type")]
+  kind: &'static str,
+  id: Url,
+  part_of: Url,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  next: Option<Url>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  prev: Option<Url>,
+  ordered_items: Vec<CreateActivity>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateActivity {
+  #[serde(rename = "type")]
+  kind: &'static str,
+  id: Url,
+  actor: Url,
+  object: <MyPost as ApubObject>::ApubType,
+}
+
 async fn post_inbox(
   request: HttpRequest,
   payload: String,
@@ -102,4 +359,329 @@ async fn post_inbox(
     )
     .await?,
   )
+}
+
+/// A queued outgoing delivery: an unsigned activity body bound for a single remote inbox,
+/// along with whatever the sending actor needs to sign it. Signing happens in [`deliver`] on
+/// every attempt rather than once here, because a `Date`/`Signature` pair is only valid for a
+/// narrow window and must not be replayed across a retry that may happen hours later.
+#[derive(Clone)]
+struct DeliveryJob {
+  inbox_url: Url,
+  actor_id: Url,
+  private_key_pem: String,
+  activity: String,
+  attempts: u32,
+  next_attempt_at: Instant,
+}
+
+/// Snapshot of the delivery queue's size, for monitoring.
+pub struct DeliveryMetrics {
+  pub pending: usize,
+  pub dead: usize,
+}
+
+/// Storage for outgoing activity deliveries, behind a trait so that a persistent backend can
+/// implement it without changing the worker loop. The only implementation here is the in-memory
+/// [`MemoryDeliveryQueue`] that [`Instance::new`] uses, so pending jobs do not survive a restart.
+trait DeliveryQueue: Send + Sync {
+  fn enqueue(&self, inbox_url: Url, actor_id: Url, private_key_pem: String, activity: String);
+  /// Removes and returns all jobs that are due to be (re-)attempted right now.
+  fn take_ready(&self) -> Vec<DeliveryJob>;
+  /// Reschedules `job` with its attempt count incremented and backoff applied, or marks its
+  /// destination inbox dead once [`DELIVERY_MAX_ATTEMPTS`] is reached.
+  fn retry_or_kill(&self, job: DeliveryJob);
+  fn pending_count(&self) -> usize;
+  fn dead_count(&self) -> usize;
+}
+
+#[derive(Default)]
+struct MemoryDeliveryQueue {
+  pending: Mutex<Vec<DeliveryJob>>,
+  /// Inboxes that have exhausted [`DELIVERY_MAX_ATTEMPTS`] and are no longer retried. Tracked
+  /// per destination, not per job: otherwise a fresh job to a still-dead inbox would restart at
+  /// attempt 0 and burn through another full backoff cycle with no memory of the prior failures.
+  dead: Mutex<HashSet<Url>>,
+}
+
+impl DeliveryQueue for MemoryDeliveryQueue {
+  fn enqueue(&self, inbox_url: Url, actor_id: Url, private_key_pem: String, activity: String) {
+    if self
+      .dead
+      .lock()
+      .expect("delivery queue lock poisoned")
+      .contains(&inbox_url)
+    {
+      // This destination has already exhausted its retry budget; don't restart the cycle.
+      return;
+    }
+    self
+      .pending
+      .lock()
+      .expect("delivery queue lock poisoned")
+      .push(DeliveryJob {
+        inbox_url,
+        actor_id,
+        private_key_pem,
+        activity,
+        attempts: 0,
+        next_attempt_at: Instant::now(),
+      });
+  }
+
+  fn take_ready(&self) -> Vec<DeliveryJob> {
+    let mut pending = self.pending.lock().expect("delivery queue lock poisoned");
+    let now = Instant::now();
+    let (ready, not_ready) = pending.drain(..).partition(|job| job.next_attempt_at <= now);
+    *pending = not_ready;
+    ready
+  }
+
+  fn retry_or_kill(&self, mut job: DeliveryJob) {
+    job.attempts += 1;
+    if job.attempts >= DELIVERY_MAX_ATTEMPTS {
+      self
+        .dead
+        .lock()
+        .expect("delivery queue lock poisoned")
+        .insert(job.inbox_url);
+      return;
+    }
+    job.next_attempt_at = Instant::now() + backoff_delay(job.attempts);
+    self
+      .pending
+      .lock()
+      .expect("delivery queue lock poisoned")
+      .push(job);
+  }
+
+  fn pending_count(&self) -> usize {
+    self.pending.lock().expect("delivery queue lock poisoned").len()
+  }
+
+  fn dead_count(&self) -> usize {
+    self.dead.lock().expect("delivery queue lock poisoned").len()
+  }
+}
+
+/// Exponential backoff with jitter: doubles [`DELIVERY_RETRY_BASE`] per attempt, capped at
+/// [`DELIVERY_RETRY_MAX`], with up to 25% jitter so retries to many dead inboxes don't all
+/// land in the same instant.
+fn backoff_delay(attempts: u32) -> Duration {
+  let exponential = DELIVERY_RETRY_BASE
+    .saturating_mul(1u32 << attempts.saturating_sub(1).min(16))
+    .min(DELIVERY_RETRY_MAX);
+  exponential + Duration::from_millis(jitter_ms(exponential.as_millis() as u64 / 4))
+}
+
+static JITTER_SEED: AtomicU64 = AtomicU64::new(0);
+
+/// Cheap, non-cryptographic jitter source; avoids pulling in a `rand` dependency for what is
+/// just retry spreading.
+fn jitter_ms(bound: u64) -> u64 {
+  if bound == 0 {
+    return 0;
+  }
+  let seed = JITTER_SEED.fetch_add(1, Ordering::Relaxed);
+  seed
+    .wrapping_mul(2_654_435_761)
+    .wrapping_add(0x9E3779B97F4A7C15)
+    % bound
+}
+
+/// Spawns the background task that drains the delivery queue, sending each ready job and
+/// rescheduling it with backoff on failure.
+fn spawn_delivery_worker(queue: Arc<dyn DeliveryQueue>) {
+  tokio::spawn(async move {
+    let client = Client::default();
+    loop {
+      for job in queue.take_ready() {
+        match deliver(&client, &job).await {
+          Ok(()) => {}
+          Err(_) => queue.retry_or_kill(job),
+        }
+      }
+      tokio::time::sleep(DELIVERY_WORKER_INTERVAL).await;
+    }
+  });
+}
+
+async fn deliver(client: &Client, job: &DeliveryJob) -> Result<(), Error> {
+  let headers = sign_request(
+    &job.inbox_url,
+    &job.actor_id,
+    &job.private_key_pem,
+    &job.activity,
+  )?;
+
+  let mut request = client
+    .post(job.inbox_url.clone())
+    .header("Content-Type", APUB_JSON_CONTENT_TYPE);
+  for (name, value) in headers {
+    request = request.header(name, value);
+  }
+
+  let response = request.body(job.activity.clone()).send().await?;
+  if response.status().is_success() {
+    Ok(())
+  } else {
+    Err(anyhow!(
+      "delivery to {} failed with status {}",
+      job.inbox_url,
+      response.status()
+    ))
+  }
+}
+
+/// Builds the `Host`/`Date`/`Digest`/`Signature` headers for an inbox delivery: the `Digest` that
+/// a peer's `VerifyDigest` middleware (see [`Instance::listen`]) checks against the body, and an
+/// HTTP signature over the request target, host, date and digest. Without these, a
+/// delivery to any server enforcing HTTP signatures — which is the norm, not the exception — is
+/// rejected outright rather than merely untrusted.
+fn sign_request(
+  inbox_url: &Url,
+  actor_id: &Url,
+  private_key_pem: &str,
+  body: &str,
+) -> Result<Vec<(&'static str, String)>, Error> {
+  let host = inbox_url
+    .host_str()
+    .ok_or_else(|| anyhow!("inbox url {inbox_url} has no host"))?
+    .to_string();
+  let path = inbox_url.path();
+  let digest = format!(
+    "SHA-256={}",
+    base64::encode(Sha256::digest(body.as_bytes()))
+  );
+  let date = httpdate::fmt_http_date(std::time::SystemTime::now());
+
+  let signing_string =
+    format!("(request-target): post {path}\nhost: {host}\ndate: {date}\ndigest: {digest}");
+  let private_key = PKey::private_key_from_pem(private_key_pem.as_bytes())?;
+  let mut signer = Signer::new(MessageDigest::sha256(), &private_key)?;
+  signer.update(signing_string.as_bytes())?;
+  let signature = base64::encode(signer.sign_to_vec()?);
+
+  let signature_header = format!(
+    "keyId=\"{actor_id}\",algorithm=\"rsa-sha256\",headers=\"(request-target) host date digest\",signature=\"{signature}\""
+  );
+
+  Ok(vec![
+    ("Host", host),
+    ("Date", date),
+    ("Digest", digest),
+    ("Signature", signature_header),
+  ])
+}
+
+/// Shared inbox used for activities that are addressed to many local followers at once (e.g. an
+/// `Announce` of a community post). Activities are deduplicated by id so that a delivery which
+/// fans out to several local recipients is only processed once.
+async fn post_shared_inbox(
+  request: HttpRequest,
+  payload: String,
+  local_instance: web::Data<Arc<LocalInstance>>,
+  seen_activities: web::Data<Arc<Mutex<SeenActivities>>>,
+) -> Result<HttpResponse, LemmyError> {
+  let activity_id = read_activity_id(&payload)?;
+
+  // Claim the id before processing, not after: two deliveries of the same Announce can arrive
+  // concurrently (that's the whole scenario this route exists for), and if both passed a
+  // `contains` check before either recorded the id, both would call `receive_activity` below.
+  // `insert_if_new` claims the id atomically under the same lock acquisition that checks it.
+  let first_seen = seen_activities
+    .lock()
+    .expect("seen activities lock poisoned")
+    .insert_if_new(activity_id.clone());
+  if !first_seen {
+    // Already claimed (and being, or already, processed) by another delivery of this id. Ack
+    // without reprocessing so the sending server doesn't retry the delivery.
+    return Ok(HttpResponse::Ok().finish());
+  }
+
+  let activity = serde_json::from_str(&payload)?;
+  let result = receive_activity::<WithContext<PersonAcceptedActivities>, MyUser, ()>(
+    request,
+    activity,
+    local_instance.deref(),
+    &Data::new(()),
+  )
+  .await;
+
+  if result.is_err() {
+    // Processing failed, so this delivery never actually took effect: release the claim so a
+    // retried delivery of the same activity id is processed rather than silently dropped.
+    seen_activities
+      .lock()
+      .expect("seen activities lock poisoned")
+      .remove(&activity_id);
+  }
+
+  Ok(result?)
+}
+
+fn read_activity_id(payload: &str) -> Result<Url, LemmyError> {
+  #[derive(Deserialize)]
+  struct ActivityId {
+    id: Url,
+  }
+  Ok(serde_json::from_str::<ActivityId>(payload)?.id)
+}
+
+/// Bounded, in-memory dedup cache for the shared inbox. Remembers the most recently seen
+/// activity ids so duplicate deliveries are skipped, evicting the oldest entries once the
+/// cache is full or they outlive `ttl` so memory use stays bounded.
+struct SeenActivities {
+  capacity: usize,
+  ttl: Duration,
+  order: VecDeque<Url>,
+  seen: HashMap<Url, Instant>,
+}
+
+impl SeenActivities {
+  fn new(capacity: usize, ttl: Duration) -> Self {
+    SeenActivities {
+      capacity,
+      ttl,
+      order: VecDeque::new(),
+      seen: HashMap::new(),
+    }
+  }
+
+  /// Records `id` as seen, returning `true` if this is the first time it has been observed
+  /// and `false` if it was already present (and should not be reprocessed).
+  fn insert_if_new(&mut self, id: Url) -> bool {
+    self.evict_expired();
+    if self.seen.contains_key(&id) {
+      return false;
+    }
+    if self.order.len() >= self.capacity {
+      if let Some(oldest) = self.order.pop_front() {
+        self.seen.remove(&oldest);
+      }
+    }
+    self.order.push_back(id.clone());
+    self.seen.insert(id, Instant::now());
+    true
+  }
+
+  /// Releases a previously claimed id, e.g. because processing it failed and a retried
+  /// delivery of the same id should not be dropped as a duplicate.
+  fn remove(&mut self, id: &Url) {
+    if self.seen.remove(id).is_some() {
+      self.order.retain(|seen_id| seen_id != id);
+    }
+  }
+
+  fn evict_expired(&mut self) {
+    while let Some(oldest) = self.order.front() {
+      match self.seen.get(oldest) {
+        Some(seen_at) if seen_at.elapsed() > self.ttl => {
+          let expired = self.order.pop_front().expect("checked by front()");
+          self.seen.remove(&expired);
+        }
+        _ => break,
+      }
+    }
+  }
 }
\ No newline at end of file
@@ -3,10 +3,18 @@ use crate::{
   source::{actor_language::*, community::Community, language::Language, site::Site},
 };
 use diesel::{
-  delete, dsl::*, insert_into, result::Error, select, ExpressionMethods, PgConnection, QueryDsl,
-  RunQueryDsl,
+  delete, dsl::*, insert_into, result::Error, select, ExpressionMethods, OptionalExtension,
+  PgConnection, QueryDsl, RunQueryDsl,
 };
 use lemmy_utils::error::LemmyError;
+use whatlang::{detect as detect_lang, Lang};
+
+/// The `LanguageId` of the "Undetermined" language, seeded as a neutral placeholder that is
+/// always allowed regardless of a community's configured languages.
+pub const UNDETERMINED_ID: LanguageId = LanguageId(0);
+
+/// Below this many significant characters, language detection is too unreliable to trust.
+const MIN_DETECTABLE_CHARS: usize = 10;
 
 impl LocalUserLanguage {
   pub fn read(
@@ -35,15 +43,16 @@ impl LocalUserLanguage {
       delete(local_user_language.filter(local_user_id.eq(for_local_user_id))).execute(conn)?;
 
       let lang_ids = update_languages(conn, language_ids)?;
-      for l in lang_ids {
-        let form = LocalUserLanguageForm {
+      let forms = lang_ids
+        .into_iter()
+        .map(|l| LocalUserLanguageForm {
           local_user_id: for_local_user_id,
           language_id: l,
-        };
-        insert_into(local_user_language)
-          .values(form)
-          .get_result::<Self>(conn)?;
-      }
+        })
+        .collect::<Vec<_>>();
+      insert_into(local_user_language)
+        .values(forms)
+        .execute(conn)?;
       Ok(())
     })
   }
@@ -76,15 +85,14 @@ impl SiteLanguage {
       delete(site_language.filter(site_id.eq(for_site_id))).execute(conn)?;
 
       let lang_ids = update_languages(conn, language_ids)?;
-      for l in lang_ids.clone() {
-        let form = SiteLanguageForm {
+      let forms = lang_ids
+        .iter()
+        .map(|l| SiteLanguageForm {
           site_id: for_site_id,
-          language_id: l,
-        };
-        insert_into(site_language)
-          .values(form)
-          .get_result::<Self>(conn)?;
-      }
+          language_id: *l,
+        })
+        .collect::<Vec<_>>();
+      insert_into(site_language).values(forms).execute(conn)?;
 
       CommunityLanguage::limit_languages(conn, lang_ids)?;
 
@@ -100,6 +108,12 @@ impl CommunityLanguage {
     for_language_id: LanguageId,
     for_community_id: CommunityId,
   ) -> Result<(), LemmyError> {
+    // "Undetermined" is a neutral placeholder used when the language of some content
+    // couldn't be determined, and is never restricted by community language settings.
+    if for_language_id == UNDETERMINED_ID {
+      return Ok(());
+    }
+
     use crate::schema::community_language::dsl::*;
     let is_allowed = select(exists(
       community_language
@@ -162,20 +176,112 @@ impl CommunityLanguage {
       delete(community_language.filter(community_id.eq(for_community_id))).execute(conn)?;
 
       let lang_ids = update_languages(conn, language_ids)?;
-      for l in lang_ids {
-        let form = CommunityLanguageForm {
+      let forms = lang_ids
+        .into_iter()
+        .map(|l| CommunityLanguageForm {
           community_id: for_community_id,
           language_id: l,
-        };
-        insert_into(community_language)
-          .values(form)
-          .get_result::<Self>(conn)?;
-      }
+        })
+        .collect::<Vec<_>>();
+      insert_into(community_language)
+        .values(forms)
+        .execute(conn)?;
       Ok(())
     })
   }
 }
 
+/// Detects the most probable language of `text` using a statistical n-gram classifier (the
+/// approach used by `whatlang`/CLD: score the text against per-language trigram profiles and
+/// pick the highest-scoring candidate). Falls back to [`UNDETERMINED_ID`] when `text` is
+/// shorter than [`MIN_DETECTABLE_CHARS`], or when the top two candidates are too close to
+/// call reliably.
+pub fn detect_content_language(
+  conn: &mut PgConnection,
+  text: &str,
+) -> Result<LanguageId, LemmyError> {
+  if text.chars().filter(|c| !c.is_whitespace()).count() < MIN_DETECTABLE_CHARS {
+    return Ok(UNDETERMINED_ID);
+  }
+
+  let detected = match detect_lang(text) {
+    Some(info) if info.is_reliable() => info.lang(),
+    _ => return Ok(UNDETERMINED_ID),
+  };
+
+  // `whatlang::Lang::code()` returns an ISO 639-3 (three-letter) code, but the `language`
+  // table is seeded with the ISO 639-1 (two-letter) codes Lemmy uses everywhere else, so the
+  // two must be translated explicitly rather than compared as-is.
+  let iso_639_1 = match to_iso_639_1(detected) {
+    Some(iso_639_1) => iso_639_1,
+    None => return Ok(UNDETERMINED_ID),
+  };
+
+  use crate::schema::language::dsl::*;
+  let found_id = language
+    .filter(code.eq(iso_639_1))
+    .select(id)
+    .first::<LanguageId>(conn)
+    .optional()?;
+
+  Ok(found_id.unwrap_or(UNDETERMINED_ID))
+}
+
+/// Translates a `whatlang` detected language into the ISO 639-1 code used by the `language`
+/// table. Covers the languages `whatlang` is most confident distinguishing; anything else
+/// falls back to [`UNDETERMINED_ID`] rather than risk matching the wrong row.
+fn to_iso_639_1(lang: Lang) -> Option<&'static str> {
+  use Lang::*;
+  Some(match lang {
+    Eng => "en",
+    Spa => "es",
+    Fra => "fr",
+    Deu => "de",
+    Ita => "it",
+    Por => "pt",
+    Rus => "ru",
+    Ukr => "uk",
+    Pol => "pl",
+    Ces => "cs",
+    Nld => "nl",
+    Swe => "sv",
+    Dan => "da",
+    Fin => "fi",
+    Ell => "el",
+    Tur => "tr",
+    Vie => "vi",
+    Cmn => "zh",
+    Jpn => "ja",
+    Kor => "ko",
+    Arb => "ar",
+    Hin => "hi",
+    _ => return None,
+  })
+}
+
+/// Resolves the language to store for new content: uses `language_id` if the caller supplied
+/// one, otherwise detects it from `content`. The result is validated against the community's
+/// allowed languages via [`CommunityLanguage::is_allowed_community_language`] before the
+/// caller inserts the post or comment.
+///
+/// Nothing in this module calls this function; the detection and the community language check
+/// take place only where a caller invokes it.
+pub fn resolve_content_language(
+  conn: &mut PgConnection,
+  language_id: Option<LanguageId>,
+  content: &str,
+  for_community_id: CommunityId,
+) -> Result<LanguageId, LemmyError> {
+  let language_id = match language_id {
+    Some(language_id) => language_id,
+    None => detect_content_language(conn, content)?,
+  };
+
+  CommunityLanguage::is_allowed_community_language(conn, language_id, for_community_id)?;
+
+  Ok(language_id)
+}
+
 // If no language is given, set all languages
 fn update_languages(
   conn: &mut PgConnection,
@@ -192,3 +298,27 @@ fn update_languages(
     Ok(language_ids)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Each mapped code must be the ISO 639-1 code Lemmy's seeded `language` table actually
+  /// uses, not `whatlang`'s native ISO 639-3 code, or `detect_content_language` would look up
+  /// the wrong row (or none) for every detected language.
+  #[test]
+  fn to_iso_639_1_matches_language_table_codes() {
+    assert_eq!(to_iso_639_1(Lang::Eng), Some("en"));
+    assert_eq!(to_iso_639_1(Lang::Spa), Some("es"));
+    assert_eq!(to_iso_639_1(Lang::Deu), Some("de"));
+    assert_eq!(to_iso_639_1(Lang::Cmn), Some("zh"));
+    assert_eq!(to_iso_639_1(Lang::Arb), Some("ar"));
+  }
+
+  /// Languages we haven't explicitly mapped must fall back to `None` (and therefore
+  /// `UNDETERMINED_ID`) instead of silently matching an unrelated `language` row.
+  #[test]
+  fn to_iso_639_1_falls_back_for_unmapped_languages() {
+    assert_eq!(to_iso_639_1(Lang::Epo), None);
+  }
+}